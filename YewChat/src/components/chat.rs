@@ -1,11 +1,23 @@
+use std::collections::{HashMap, HashSet};
+
+use gloo_timers::callback::{Interval, Timeout};
+use pulldown_cmark::{html, Options, Parser};
 use serde::{Deserialize, Serialize};
-use web_sys::HtmlInputElement;
+use uuid::Uuid;
+use web_sys::{HtmlElement, HtmlInputElement, ScrollBehavior, ScrollIntoViewOptions, ScrollLogicalPosition};
 use yew::prelude::*;
 use yew_agent::{Bridge, Bridged};
 
 use crate::services::event_bus::EventBus;
 use crate::{services::websocket::WebsocketService, User};
 
+/// How long a "typing" entry stays fresh before it's treated as stale.
+const TYPING_TIMEOUT_MS: f64 = 3_000.0;
+/// Minimum gap between outgoing `Typing` announcements while a burst is in progress.
+const TYPING_THROTTLE_MS: u32 = 1_000;
+/// How close to the bottom (in px) the user has to be for new messages to auto-scroll.
+const AUTO_SCROLL_THRESHOLD_PX: i32 = 32;
+
 fn is_single_emoji(text: &str) -> bool {
     let trimmed = text.trim();
     let char_count = trimmed.chars().count();
@@ -29,15 +41,92 @@ fn is_single_emoji(text: &str) -> bool {
     }
 }
 
+/// Renders `text` as sanitized markdown HTML, allowing only a safe subset of
+/// tags/attributes so a message can't inject scripts, iframes, or styling.
+fn render_markdown(text: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+
+    let parser = Parser::new_ext(text, options);
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::Builder::default()
+        .tags(
+            [
+                "p", "br", "strong", "em", "code", "pre", "blockquote", "ul", "ol", "li", "a",
+                "h1", "h2", "h3", "h4", "h5", "h6", "del", "table", "thead", "tbody", "tr", "th",
+                "td",
+            ]
+            .into_iter()
+            .collect(),
+        )
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .clean(&unsafe_html)
+        .to_string()
+}
+
 pub enum Msg {
     HandleMsg(String),
     SubmitMessage,
+    InputChanged,
+    SendTyping,
+    ExpireTyping,
+    ToggleWhisperTarget(String),
+    MessagesScrolled,
+    Tick,
+    EndTypingThrottle,
 }
 
 #[derive(Deserialize)]
 struct MessageData {
     from: String,
     message: String,
+    #[serde(default)]
+    to: Option<String>,
+    #[serde(default)]
+    is_private: bool,
+    /// Unix millis the message was sent at; falls back to receipt time if the
+    /// server didn't stamp it.
+    #[serde(default = "js_sys::Date::now")]
+    timestamp: f64,
+    /// Copied over from the enclosing `WebSocketMessage`, not part of its own JSON.
+    #[serde(default)]
+    id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AckData {
+    id: String,
+    by: String,
+    /// Set to the original sender when acking a whisper, so the server routes
+    /// the ack point-to-point instead of broadcasting it like a public ack.
+    #[serde(default)]
+    to: Option<String>,
+}
+
+/// Formats a unix-millis timestamp relative to now, e.g. "just now", "3m ago",
+/// "2h ago", or a clock time once it's more than a day old.
+fn relative_time(ts: f64) -> String {
+    let diff_secs = ((js_sys::Date::now() - ts) / 1000.0).max(0.0) as i64;
+    match diff_secs {
+        s if s < 60 => "just now".to_string(),
+        s if s < 3_600 => format!("{}m ago", s / 60),
+        s if s < 86_400 => format!("{}h ago", s / 3_600),
+        _ => {
+            let date = js_sys::Date::new(&wasm_bindgen::JsValue::from_f64(ts));
+            format!("{:02}:{:02}", date.get_hours(), date.get_minutes())
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WhisperData {
+    to: String,
+    message: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -46,6 +135,9 @@ pub enum MsgTypes {
     Users,
     Register,
     Message,
+    Typing,
+    Whisper,
+    Ack,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -54,6 +146,8 @@ struct WebSocketMessage {
     message_type: MsgTypes,
     data_array: Option<Vec<String>>,
     data: Option<String>,
+    #[serde(default)]
+    id: Option<String>,
 }
 
 #[derive(Clone)]
@@ -68,7 +162,50 @@ pub struct Chat {
     _producer: Box<dyn Bridge<EventBus>>,
     wss: WebsocketService,
     messages: Vec<MessageData>,
+    username: String,
+    /// Last time (ms since epoch) each user was seen typing.
+    typing: HashMap<String, f64>,
+    /// Set while a `Typing` announcement is throttled, so bursts of keystrokes
+    /// don't flood the socket with one `Typing` message per keystroke.
+    typing_throttle: Option<Timeout>,
+    _typing_expiry: Interval,
+    /// User currently selected to whisper to, if any.
+    whisper_target: Option<String>,
+    messages_container: NodeRef,
+    /// Whether the user was scrolled near the bottom before the last render.
+    near_bottom: bool,
+    /// Forces a periodic re-render so relative timestamps keep aging.
+    _relabel_tick: Interval,
+    /// Usernames who have acknowledged each message id.
+    acks: HashMap<String, HashSet<String>>,
 }
+impl Chat {
+    /// Tells the sender we've received a message with the given id. `to` scopes
+    /// delivery to that sender alone (used for whispers); `None` broadcasts,
+    /// matching how public messages are relayed.
+    fn send_ack(&self, id: String, to: Option<String>) {
+        let ack = AckData {
+            id,
+            by: self.username.clone(),
+            to,
+        };
+        let message = WebSocketMessage {
+            message_type: MsgTypes::Ack,
+            data: Some(serde_json::to_string(&ack).unwrap()),
+            data_array: None,
+            id: None,
+        };
+        if let Err(e) = self
+            .wss
+            .tx
+            .clone()
+            .try_send(serde_json::to_string(&message).unwrap())
+        {
+            log::debug!("error sending to channel: {:?}", e);
+        }
+    }
+}
+
 impl Component for Chat {
     type Message = Msg;
     type Properties = ();
@@ -85,6 +222,7 @@ impl Component for Chat {
             message_type: MsgTypes::Register,
             data: Some(username.to_string()),
             data_array: None,
+            id: None,
         };
 
         if let Ok(_) = wss
@@ -95,16 +233,34 @@ impl Component for Chat {
             log::debug!("message sent successfully");
         }
 
+        let typing_expiry = {
+            let link = ctx.link().clone();
+            Interval::new(1_000, move || link.send_message(Msg::ExpireTyping))
+        };
+        let relabel_tick = {
+            let link = ctx.link().clone();
+            Interval::new(30_000, move || link.send_message(Msg::Tick))
+        };
+
         Self {
             users: vec![],
             messages: vec![],
             chat_input: NodeRef::default(),
             wss,
+            username,
+            typing: HashMap::new(),
+            typing_throttle: None,
+            _typing_expiry: typing_expiry,
+            whisper_target: None,
+            messages_container: NodeRef::default(),
+            near_bottom: true,
+            _relabel_tick: relabel_tick,
+            acks: HashMap::new(),
             _producer: EventBus::bridge(ctx.link().callback(Msg::HandleMsg)),
         }
     }
 
-    fn update(&mut self, _ctx: &Context<Self>, msg: Self::Message) -> bool {
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
             Msg::HandleMsg(s) => {
                 let msg: WebSocketMessage = serde_json::from_str(&s).unwrap();
@@ -125,11 +281,39 @@ impl Component for Chat {
                         return true;
                     }
                     MsgTypes::Message => {
-                        let message_data: MessageData =
+                        let mut message_data: MessageData =
                             serde_json::from_str(&msg.data.unwrap()).unwrap();
+                        message_data.id = msg.id.unwrap_or_default();
+                        if message_data.from != self.username && !message_data.id.is_empty() {
+                            self.send_ack(message_data.id.clone(), None);
+                        }
                         self.messages.push(message_data);
                         return true;
                     }
+                    MsgTypes::Typing => {
+                        if let Some(from) = msg.data {
+                            if from != self.username {
+                                self.typing.insert(from, js_sys::Date::now());
+                            }
+                        }
+                        return true;
+                    }
+                    MsgTypes::Whisper => {
+                        let mut message_data: MessageData =
+                            serde_json::from_str(&msg.data.unwrap()).unwrap();
+                        message_data.id = msg.id.unwrap_or_default();
+                        message_data.is_private = true;
+                        if message_data.from != self.username && !message_data.id.is_empty() {
+                            self.send_ack(message_data.id.clone(), Some(message_data.from.clone()));
+                        }
+                        self.messages.push(message_data);
+                        return true;
+                    }
+                    MsgTypes::Ack => {
+                        let ack: AckData = serde_json::from_str(&msg.data.unwrap()).unwrap();
+                        self.acks.entry(ack.id).or_default().insert(ack.by);
+                        return true;
+                    }
                     _ => {
                         return false;
                     }
@@ -138,10 +322,26 @@ impl Component for Chat {
             Msg::SubmitMessage => {
                 let input = self.chat_input.cast::<HtmlInputElement>();
                 if let Some(input) = input {
-                    let message = WebSocketMessage {
-                        message_type: MsgTypes::Message,
-                        data: Some(input.value()),
-                        data_array: None,
+                    let id = Some(Uuid::new_v4().to_string());
+                    let message = match &self.whisper_target {
+                        Some(to) => {
+                            let whisper = WhisperData {
+                                to: to.clone(),
+                                message: input.value(),
+                            };
+                            WebSocketMessage {
+                                message_type: MsgTypes::Whisper,
+                                data: Some(serde_json::to_string(&whisper).unwrap()),
+                                data_array: None,
+                                id,
+                            }
+                        }
+                        None => WebSocketMessage {
+                            message_type: MsgTypes::Message,
+                            data: Some(input.value()),
+                            data_array: None,
+                            id,
+                        },
                     };
                     if let Err(e) = self
                         .wss
@@ -155,6 +355,74 @@ impl Component for Chat {
                 };
                 false
             }
+            Msg::ToggleWhisperTarget(name) => {
+                self.whisper_target = if self.whisper_target.as_deref() == Some(name.as_str()) {
+                    None
+                } else {
+                    Some(name)
+                };
+                true
+            }
+            Msg::InputChanged => {
+                if self.typing_throttle.is_none() {
+                    ctx.link().send_message(Msg::SendTyping);
+                    let link = ctx.link().clone();
+                    self.typing_throttle = Some(Timeout::new(TYPING_THROTTLE_MS, move || {
+                        link.send_message(Msg::EndTypingThrottle)
+                    }));
+                }
+                false
+            }
+            Msg::EndTypingThrottle => {
+                self.typing_throttle = None;
+                false
+            }
+            Msg::SendTyping => {
+                let message = WebSocketMessage {
+                    message_type: MsgTypes::Typing,
+                    data: Some(self.username.clone()),
+                    data_array: None,
+                    id: None,
+                };
+                if let Err(e) = self
+                    .wss
+                    .tx
+                    .clone()
+                    .try_send(serde_json::to_string(&message).unwrap())
+                {
+                    log::debug!("error sending to channel: {:?}", e);
+                }
+                false
+            }
+            Msg::ExpireTyping => {
+                let now = js_sys::Date::now();
+                let before = self.typing.len();
+                self.typing
+                    .retain(|_, last_seen| now - *last_seen < TYPING_TIMEOUT_MS);
+                self.typing.len() != before
+            }
+            Msg::MessagesScrolled => {
+                if let Some(container) = self.messages_container.cast::<HtmlElement>() {
+                    self.near_bottom = container.scroll_top() + container.client_height()
+                        >= container.scroll_height() - AUTO_SCROLL_THRESHOLD_PX;
+                }
+                false
+            }
+            Msg::Tick => true,
+        }
+    }
+
+    fn rendered(&mut self, _ctx: &Context<Self>, _first_render: bool) {
+        if !self.near_bottom {
+            return;
+        }
+        if let Some(container) = self.messages_container.cast::<HtmlElement>() {
+            if let Some(last) = container.last_element_child() {
+                let opts = ScrollIntoViewOptions::new();
+                opts.set_behavior(ScrollBehavior::Smooth);
+                opts.set_block(ScrollLogicalPosition::End);
+                last.scroll_into_view_with_scroll_into_view_options(&opts);
+            }
         }
     }
 
@@ -170,8 +438,12 @@ impl Component for Chat {
                     <div class="p-3 space-y-2 overflow-y-auto h-full">
                         {
                             self.users.clone().iter().map(|u| {
+                                let is_target = self.whisper_target.as_deref() == Some(u.name.as_str());
+                                let name = u.name.clone();
+                                let onclick = ctx.link().callback(move |_| Msg::ToggleWhisperTarget(name.clone()));
+                                let border = if is_target { "border-purple-400 ring-2 ring-purple-200" } else { "border-gray-100" };
                                 html!{
-                                    <div class="flex items-center p-3 hover:bg-gray-50 rounded-xl transition-colors duration-200 border border-gray-100 shadow-sm">
+                                    <div {onclick} class={classes!("flex", "items-center", "p-3", "hover:bg-gray-50", "rounded-xl", "transition-colors", "duration-200", "border", "shadow-sm", "cursor-pointer", border)}>
                                         <div class="relative">
                                             <img class="w-10 h-10 rounded-full border-2 border-green-400" src={u.avatar.clone()} alt="avatar"/>
                                             <div class="absolute -bottom-1 -right-1 w-4 h-4 bg-green-400 rounded-full border-2 border-white"></div>
@@ -181,7 +453,7 @@ impl Component for Chat {
                                                 {u.name.clone()}
                                             </div>
                                             <div class="text-xs text-green-500 font-medium">
-                                                {"Online"}
+                                                {if is_target { "Whispering…" } else { "Online" }}
                                             </div>
                                         </div>
                                     </div>
@@ -207,17 +479,37 @@ impl Component for Chat {
                     </div>
                     
                     // Messages area
-                    <div class="flex-1 overflow-y-auto p-4 space-y-4 bg-gradient-to-b from-slate-50 to-blue-50">
+                    <div
+                        ref={self.messages_container.clone()}
+                        onscroll={ctx.link().callback(|_: Event| Msg::MessagesScrolled)}
+                        class="flex-1 overflow-y-auto p-4 space-y-4 bg-gradient-to-b from-slate-50 to-blue-50"
+                    >
                         {
                             self.messages.iter().map(|m| {
                                 let user = self.users.iter().find(|u| u.name == m.from).unwrap();
+                                let bubble = if m.is_private {
+                                    "bg-purple-50 rounded-2xl rounded-tl-sm shadow-md border border-purple-200 p-4 flex-1"
+                                } else {
+                                    "bg-white rounded-2xl rounded-tl-sm shadow-md border border-gray-100 p-4 flex-1"
+                                };
                                 html!{
                                     <div class="flex items-start space-x-3 max-w-2xl">
                                         <img class="w-8 h-8 rounded-full border-2 border-white shadow-md flex-shrink-0" src={user.avatar.clone()} alt="avatar"/>
-                                        <div class="bg-white rounded-2xl rounded-tl-sm shadow-md border border-gray-100 p-4 flex-1">
+                                        <div class={bubble}>
                                             <div class="flex items-center space-x-2 mb-1">
                                                 <span class="font-semibold text-gray-800 text-sm">{m.from.clone()}</span>
-                                                <span class="text-xs text-gray-400">{"just now"}</span>
+                                                if m.is_private {
+                                                    <span class="text-xs font-medium text-purple-500">
+                                                        {
+                                                            if m.from == self.username {
+                                                                format!("🔒 whisper to {}", m.to.clone().unwrap_or_default())
+                                                            } else {
+                                                                format!("🔒 whisper from {}", m.from)
+                                                            }
+                                                        }
+                                                    </span>
+                                                }
+                                                <span class="text-xs text-gray-400">{relative_time(m.timestamp)}</span>
                                             </div>
                                             <div class="text-gray-700">
                                                 if m.message.ends_with(".gif") {
@@ -225,22 +517,57 @@ impl Component for Chat {
                                                 } else if is_single_emoji(&m.message) {
                                                     <p class="text-6xl leading-relaxed">{m.message.clone()}</p>
                                                 } else {
-                                                    <p class="leading-relaxed">{m.message.clone()}</p>
+                                                    <div class="leading-relaxed prose prose-sm max-w-none">
+                                                        {Html::from_html_unchecked(render_markdown(&m.message).into())}
+                                                    </div>
                                                 }
                                             </div>
+                                            if m.from == self.username {
+                                                {
+                                                    let seen_by = self.acks.get(&m.id);
+                                                    match seen_by {
+                                                        Some(by) if !by.is_empty() => html! {
+                                                            <div class="group relative mt-1 text-xs text-gray-400 cursor-default w-fit">
+                                                                {format!("✓ seen by {}", by.len())}
+                                                                <div class="hidden group-hover:flex absolute left-0 bottom-full mb-1 space-x-1 bg-white border border-gray-100 rounded-lg shadow-md p-1 z-10">
+                                                                    {
+                                                                        self.users.iter().filter(|u| by.contains(&u.name)).map(|u| html! {
+                                                                            <img class="w-6 h-6 rounded-full" src={u.avatar.clone()} alt={u.name.clone()} title={u.name.clone()}/>
+                                                                        }).collect::<Html>()
+                                                                    }
+                                                                </div>
+                                                            </div>
+                                                        },
+                                                        _ => html! {},
+                                                    }
+                                                }
+                                            }
                                         </div>
                                     </div>
                                 }
                             }).collect::<Html>()
                         }
+                        {
+                            if self.typing.is_empty() {
+                                html! {}
+                            } else {
+                                let names = self.typing.keys().cloned().collect::<Vec<_>>().join(", ");
+                                html! {
+                                    <div class="text-sm italic text-gray-400 pl-2">
+                                        {format!("{} typing…", names)}
+                                    </div>
+                                }
+                            }
+                        }
                     </div>
-                    
+
                     // Input area
                     <div class="bg-white border-t border-gray-200 p-4 shadow-lg">
                         <div class="flex items-center space-x-3 max-w-4xl mx-auto">
                             <div class="flex-1 relative">
-                                <input 
-                                    ref={self.chat_input.clone()} 
+                                <input
+                                    ref={self.chat_input.clone()}
+                                    oninput={ctx.link().callback(|_| Msg::InputChanged)}
                                     type="text" 
                                     placeholder="Type your message..." 
                                     class="w-full py-3 px-4 pr-12 bg-gray-100 border border-gray-200 rounded-full outline-none focus:ring-2 focus:ring-blue-500 focus:border-transparent transition-all duration-200" 